@@ -9,11 +9,51 @@
 // except according to those terms.
 
 use version::{try_getting_version, try_getting_local_version,
-              Version, NoVersion, split_version};
+              Version, NoVersion, split_version, parse_version};
 use std::hash::Streaming;
-use std::{char, hash};
+use std::{char, cmp, hash};
 use messages::error;
 
+/// Where the sources for a package come from. Carried alongside the
+/// path so that a git checkout, a registry entry, and a local RUST_PATH
+/// package can all share the same relative path without colliding.
+#[deriving(Clone, Eq)]
+pub enum SourceKind {
+    /// Found via a search of RUST_PATH, or pointed at an absolute
+    /// on-disk location with an explicit `path+file://` prefix. This
+    /// is the default when no `kind+` prefix is present.
+    Path,
+    /// Cloned from a git repository.
+    Git(GitReference),
+    /// Resolved against the default package registry.
+    Registry,
+    /// Resolved against a sparse-protocol registry. Unlike the other
+    /// kinds, the `sparse+` prefix is kept as part of the path so the
+    /// registry's URL scheme survives parsing.
+    SparseRegistry
+}
+
+/// Which git reference a `Git` source should be checked out at. Parsed
+/// off of a `?branch=`, `?tag=`, or `?rev=` suffix on the package
+/// identifier; absent any of those, the default branch is used.
+#[deriving(Clone, Eq)]
+pub enum GitReference {
+    DefaultBranch,
+    Branch(~str),
+    Tag(~str),
+    Rev(~str)
+}
+
+/// A package-identifier scheme, layered on top of the ordinary
+/// `SourceKind` prefix. Modeled on a case-insensitive prefix terminated
+/// by `:`; currently only `sys:` is recognized, marking a package as
+/// satisfied by a system install rather than built from the RUST_PATH.
+#[deriving(Clone, Eq)]
+pub enum PkgScheme {
+    NoScheme,
+    Sys
+}
+
 /// Path-fragment identifier of a package such as
 /// 'github.com/graydon/test'; path must be a relative
 /// path with >=1 component.
@@ -33,12 +73,17 @@ pub struct PkgId {
     /// of package IDs whose short names aren't valid Rust identifiers.
     short_name: ~str,
     /// The requested package version.
-    version: Version
+    version: Version,
+    /// Where this package's sources are expected to come from.
+    source_kind: SourceKind,
+    /// The `sys:`-style scheme, if any, this identifier was written with.
+    scheme: PkgScheme
 }
 
 impl Eq for PkgId {
     fn eq(&self, other: &PkgId) -> bool {
-        self.path == other.path && self.version == other.version
+        self.path == other.path && self.version == other.version &&
+            self.source_kind == other.source_kind && self.scheme == other.scheme
     }
 }
 
@@ -79,8 +124,10 @@ fn is_url_part(ch: char) -> bool {
 fn ensure_legal_package_id(s: &str) {
     let mut legal = true;
     for ch in s.iter() {
-        // Hack to ignore everything after the optional '#'
-        if ch == '#' {
+        // Hack to ignore everything after the optional '#' (a version) or
+        // '?' (a git branch/tag/rev query); both are validated -- or
+        // rejected with a clearer message -- further up in `from_prefixed`.
+        if ch == '#' || ch == '?' {
             break;
         }
         if !is_url_part(ch) {
@@ -102,64 +149,280 @@ fn ensure_legal_package_id(s: &str) {
     }
 }
 
+/// Splits off a leading `git+`, `registry+`, `sparse+`, or `path+`
+/// scheme prefix from a package identifier, returning the matching
+/// `SourceKind`, the remaining text, and whether an explicit `path+`
+/// prefix was consumed (as opposed to `Path` just being the default for
+/// no prefix at all). The `sparse+` prefix is left in place (rather than
+/// stripped) because the sparse registry's URL scheme needs to survive
+/// as part of the path. An identifier with no `+` prefix at all defaults
+/// to `Path`; any other `kind+` prefix fails.
+///
+/// The `+` is only looked for ahead of the first `#`/`?`, so a `#version`
+/// with semver build metadata (e.g. `github.com/foo/bar#1.0.0+build.5`)
+/// isn't mistaken for a `+`-prefixed scheme.
+fn parse_source_kind<'a>(s: &'a str) -> (SourceKind, &'a str, bool) {
+    let scan_limit = match (s.find('#'), s.find('?')) {
+        (Some(h), Some(q)) => cmp::min(h, q),
+        (Some(h), None) => h,
+        (None, Some(q)) => q,
+        (None, None) => s.len()
+    };
+    match s.slice_to(scan_limit).find('+') {
+        Some(i) => match s.slice_to(i) {
+            "git" => (Git(DefaultBranch), s.slice_from(i + 1), false),
+            "registry" => (Registry, s.slice_from(i + 1), false),
+            "sparse" => (SparseRegistry, s, false),
+            "path" => (Path, s.slice_from(i + 1), true),
+            other => fail!("Unknown package source kind `{}+` in package id `{}`", other, s)
+        },
+        None => (Path, s, false)
+    }
+}
+
+/// Parses a `?branch=`, `?tag=`, or `?rev=` query string (the part of a
+/// git package id after the `?`) into a `GitReference`. `whole` is only
+/// used to produce a useful error message.
+fn parse_git_reference(query: &str, whole: &str) -> GitReference {
+    match query.find('=') {
+        Some(i) => {
+            let value = query.slice_from(i + 1).to_owned();
+            match query.slice_to(i) {
+                "branch" => Branch(value),
+                "tag" => Tag(value),
+                "rev" => Rev(value),
+                other => fail!("Unknown git reference selector `{}` in package id `{}`",
+                               other, whole)
+            }
+        }
+        None => fail!("Malformed git reference `{}` in package id `{}`", query, whole)
+    }
+}
+
+/// Splits a case-insensitive `sys:`-style scheme prefix off of `s`. An
+/// identifier with no recognized scheme is `NoScheme`, matching the
+/// historical unscoped behavior.
+fn parse_package_scheme<'a>(s: &'a str) -> (PkgScheme, &'a str) {
+    match s.find(':') {
+        Some(i) if eq_ignore_ascii_case(s.slice_to(i), "sys") => (Sys, s.slice_from(i + 1)),
+        _ => (NoScheme, s)
+    }
+}
+
+fn eq_ignore_ascii_case(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).all(|(x, y)| to_ascii_lower(x) == to_ascii_lower(y))
+}
+
+fn to_ascii_lower(c: char) -> char {
+    if c >= 'A' && c <= 'Z' {
+        ((c as u8) + 32) as char
+    } else {
+        c
+    }
+}
+
+/// Splits a trailing `/<version>` path component off of `s`, e.g.
+/// `github.com/foo/bar/1.2.3` becomes (`github.com/foo/bar`, `1.2.3`).
+/// This is a second, unambiguous alternative to the `#`-delimited form
+/// handled by `split_version`; a path whose final component isn't a
+/// version (or that has no `/` at all) yields `None`. A delimiter with
+/// nothing after it (`.../bar/`) is always an error, since the user
+/// clearly meant to supply a version there.
+fn split_path_version<'a>(s: &'a str) -> Option<(&'a str, Version)> {
+    match s.rfind('/') {
+        Some(i) => {
+            let last = s.slice_from(i + 1);
+            if last.is_empty() {
+                fail!("empty package version in `{}`", s);
+            }
+            match parse_version(last) {
+                Some(v) => Some((s.slice_to(i), v)),
+                None => None
+            }
+        }
+        None => None
+    }
+}
+
+/// A short tag distinguishing each `SourceKind`, folded into `hash()` so
+/// that otherwise-identical paths from different sources don't collide.
+fn source_kind_tag(kind: &SourceKind) -> ~str {
+    match *kind {
+        Path => ~"path",
+        Git(ref r) => ~"git" + git_reference_tag(r),
+        Registry => ~"registry",
+        SparseRegistry => ~"sparse"
+    }
+}
+
+/// A short tag distinguishing each `PkgScheme`, folded into `hash()` so
+/// that a `sys:`-scoped package doesn't hash the same as an ordinary one
+/// of the same path/version/source kind.
+fn scheme_tag(scheme: &PkgScheme) -> ~str {
+    match *scheme {
+        NoScheme => ~"",
+        Sys => ~"-sys"
+    }
+}
+
+/// A short tag distinguishing each `GitReference`, so that the same repo
+/// checked out at two different branches/tags/revs doesn't produce the
+/// same hash or install directory.
+fn git_reference_tag(r: &GitReference) -> ~str {
+    match *r {
+        DefaultBranch => ~"",
+        Branch(ref b) => ~"-branch-" + *b,
+        Tag(ref t) => ~"-tag-" + *t,
+        Rev(ref v) => ~"-rev-" + *v
+    }
+}
+
+// Builds the final `PkgId` once the source kind, path, and an optional
+// user-requested version are known. Shared by every `from_prefixed`
+// branch so they all get the same filename/version-fallback handling.
+fn build_pkg_id(s: &str, path: Path, kind: SourceKind, given_version: Option<Version>,
+                 allow_absolute: bool) -> PkgId {
+    use conditions::bad_pkg_id::cond;
+
+    if !allow_absolute && !path.is_relative() {
+        return cond.raise((path, ~"absolute pkgid"));
+    }
+    if path.filename().is_none() {
+        return cond.raise((path, ~"0-length pkgid"));
+    }
+    let short_name = path.filestem_str().expect(format!("Strange path! {}", s));
+
+    let version = match given_version {
+        Some(v) => v,
+        None => match try_getting_local_version(&path) {
+            Some(v) => v,
+            None => match try_getting_version(&path) {
+                Some(v) => v,
+                None => NoVersion
+            }
+        }
+    };
+
+    PkgId {
+        path: path.clone(),
+        short_name: short_name.to_owned(),
+        version: version,
+        source_kind: kind,
+        scheme: NoScheme
+    }
+}
+
 impl PkgId {
     pub fn new(s: &str) -> PkgId {
-        use conditions::bad_pkg_id::cond;
+        let (scheme, rest) = parse_package_scheme(s);
+        let mut id = PkgId::from_prefixed(rest);
+        id.scheme = scheme;
+        id
+    }
+
+    /// Like `new`, but without the `sys:` scheme handling (the result's
+    /// scheme is always `NoScheme`). Reads a `git+`, `registry+`,
+    /// `sparse+`, or `path+file://` prefix off of `s` to pick the
+    /// `SourceKind` the rest of the identifier should be parsed as; the
+    /// `sparse+` prefix stays part of the path. An identifier with no
+    /// prefix is parsed as a `Path` source.
+    ///
+    /// A git identifier may also carry a `?branch=`, `?tag=`, or `?rev=`
+    /// suffix (e.g. `git+github.com/foo/bar?tag=v1.2`) selecting which
+    /// git reference to check out; that suffix is rejected on any other
+    /// source kind.
+    pub fn from_prefixed(s: &str) -> PkgId {
+        let (kind, rest, explicit_path_prefix) = parse_source_kind(s);
+
+        // Only an explicit `path+file://<abs>` names an absolute location
+        // directly; a bare, unprefixed `file://...` is just an ordinary
+        // (and illegal) path, same as always.
+        if explicit_path_prefix && rest.starts_with("file://") {
+            let abs = rest.slice_from("file://".len());
+            return build_pkg_id(abs, Path::new(abs), kind, None, true);
+        }
 
         // Make sure the path is a legal package ID -- it might not even
-        // be a legal path, so we do this first
-        ensure_legal_package_id(s);
+        // be a legal path, so we do this first. Identifiers with an
+        // explicit non-default source kind are trusted to know what
+        // they're doing (e.g. a `sparse+` URL legitimately contains
+        // `://`, and a `git+` URL may carry a `?branch=`/`?tag=`/`?rev=`
+        // query), so this check is only applied to the historical
+        // unprefixed/path form.
+        let rest = match kind {
+            Path => { ensure_legal_package_id(rest); rest }
+            _ => rest
+        };
 
         let mut given_version = None;
 
-        // Did the user request a specific version?
-        let s = match split_version(s) {
+        // Did the user request a specific version with the `#` delimiter?
+        // This has to run before the `?` query below is peeled off, so
+        // that a `...?branch=stable#0.9`-style id still finds its version
+        // instead of the version getting swallowed into the query string.
+        let rest = match split_version(rest) {
             Some((path, v)) => {
                 given_version = Some(v);
                 path
             }
             None => {
-                s
+                rest
             }
         };
 
-        let path = Path::new(s);
-        if !path.is_relative() {
-            return cond.raise((path, ~"absolute pkgid"));
-        }
-        if path.filename().is_none() {
-            return cond.raise((path, ~"0-length pkgid"));
-        }
-        let short_name = path.filestem_str().expect(format!("Strange path! {}", s));
+        // Does a git source pin a branch/tag/rev via a `?key=value` query?
+        // Only git identifiers may carry one; anything else is rejected.
+        // This has to run before the trailing `/<version>` check below, so
+        // that a `.../bar/1.2.3?branch=dev`-style id doesn't leave the
+        // query string glued onto what should be the version component.
+        let (rest, kind) = match rest.find('?') {
+            Some(i) => match kind {
+                Git(_) => {
+                    let path = rest.slice_to(i);
+                    let query = rest.slice_from(i + 1);
+                    (path, Git(parse_git_reference(query, rest)))
+                }
+                _ => fail!("Only git package ids can select a branch/tag/rev \
+                            (found `?{}` in `{}`)", rest.slice_from(i + 1), rest)
+            },
+            None => (rest, kind)
+        };
 
-        let version = match given_version {
-            Some(v) => v,
-            None => match try_getting_local_version(&path) {
-                Some(v) => v,
-                None => match try_getting_version(&path) {
-                    Some(v) => v,
-                    None => NoVersion
+        // Or with a trailing `/<version>` path component instead?
+        let rest = if given_version.is_none() {
+            match split_path_version(rest) {
+                Some((path, v)) => {
+                    given_version = Some(v);
+                    path
                 }
+                None => rest
             }
+        } else {
+            rest
         };
 
-        PkgId {
-            path: path.clone(),
-            short_name: short_name.to_owned(),
-            version: version
-        }
+        build_pkg_id(rest, Path::new(rest), kind, given_version, false)
     }
 
     pub fn hash(&self) -> ~str {
         // FIXME (#9639): hash should take a &[u8] so we can hash the real path
         do self.path.display().with_str |s| {
             let vers = self.version.to_str();
-            format!("{}-{}-{}", s, hash(s + vers), vers)
+            let kind = source_kind_tag(&self.source_kind);
+            let scheme = scheme_tag(&self.scheme);
+            format!("{}-{}-{}", s, hash(s + vers + kind + scheme), vers)
         }
     }
 
     pub fn short_name_with_version(&self) -> ~str {
-        format!("{}{}", self.short_name, self.version.to_str())
+        let reference = match self.source_kind {
+            Git(ref r) => git_reference_tag(r),
+            _ => ~""
+        };
+        format!("{}{}{}", self.short_name, self.version.to_str(), reference)
     }
 
     /// True if the ID has multiple components
@@ -167,6 +430,13 @@ impl PkgId {
         self.short_name.as_bytes() != self.path.as_vec()
     }
 
+    /// True if this package is satisfied by a system install (the
+    /// `sys:` scheme) rather than needing its sources cloned and built
+    /// from the RUST_PATH.
+    pub fn is_system(&self) -> bool {
+        self.scheme == Sys
+    }
+
     pub fn prefixes_iter(&self) -> Prefixes {
         prefixes_iter(&self.path)
     }
@@ -210,7 +480,31 @@ impl Iterator<(Path, Path)> for Prefixes {
 impl ToStr for PkgId {
     fn to_str(&self) -> ~str {
         // should probably use the filestem and not the whole path
-        format!("{}-{}", self.path.as_str().unwrap(), self.version.to_str())
+        //
+        // `Git`/`Registry` paths don't otherwise carry any marker of
+        // their source, so re-add the prefix here; a `SparseRegistry`
+        // path already has its `sparse+` scheme embedded and needs no
+        // further tagging.
+        let scheme_prefix = match self.scheme {
+            NoScheme => ~"",
+            Sys => ~"sys:"
+        };
+        let kind_prefix = match self.source_kind {
+            Path => ~"",
+            Git(_) => ~"git+",
+            Registry => ~"registry+",
+            SparseRegistry => ~""
+        };
+        // Fold the git reference in as a suffix (mirroring
+        // `short_name_with_version`) so that two tags/branches of the
+        // same repo don't render -- and so don't hash or install -- the
+        // same way.
+        let reference_suffix = match self.source_kind {
+            Git(ref r) => git_reference_tag(r),
+            _ => ~""
+        };
+        format!("{}{}{}-{}{}", scheme_prefix, kind_prefix, self.path.as_str().unwrap(),
+                self.version.to_str(), reference_suffix)
     }
 }
 
@@ -225,3 +519,158 @@ pub fn hash(data: ~str) -> ~str {
     hasher.result_str()
 }
 
+// Tests are split one module per backlog request, so each request's
+// commit is the natural place to look for its own coverage; a handful of
+// cases that test how two requests' delimiters interact are noted as
+// such and live with whichever request introduced the later delimiter.
+
+#[cfg(test)]
+mod test_chunk0_1 {
+    use super::PkgId;
+
+    #[test]
+    fn test_plain_path_has_no_kind_prefix() {
+        let id = PkgId::new("github.com/mozilla/quux");
+        assert_eq!(id.short_name, ~"quux");
+        assert!(!id.to_str().starts_with("git+"));
+    }
+
+    #[test]
+    fn test_git_prefix() {
+        let id = PkgId::new("git+github.com/mozilla/quux");
+        assert!(id.to_str().starts_with("git+"));
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_unknown_source_kind_prefix_fails() {
+        PkgId::new("bogus+github.com/mozilla/quux");
+    }
+
+    #[test]
+    fn test_sparse_registry_keeps_scheme_embedded_in_path() {
+        let id = PkgId::new("sparse+https://example.com/index/quux");
+        assert!(id.to_str().starts_with("sparse+https://"));
+    }
+
+    #[test]
+    fn test_path_prefix_file_url_allows_absolute_path() {
+        let id = PkgId::new("path+file:///abs/quux");
+        assert_eq!(id.short_name, ~"quux");
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_bare_file_url_without_path_prefix_is_still_illegal() {
+        PkgId::new("file:///abs/quux");
+    }
+
+    #[test]
+    fn test_plus_in_version_build_metadata_is_not_a_source_kind_prefix() {
+        // The '+' here is semver build metadata on the version, after the
+        // '#' delimiter, not a 'git+'/'registry+'/... scheme prefix.
+        let id = PkgId::new("github.com/mozilla/quux#1.0.0+build.5");
+        assert_eq!(id.short_name, ~"quux");
+    }
+}
+
+#[cfg(test)]
+mod test_chunk0_2 {
+    use super::PkgId;
+
+    #[test]
+    fn test_git_tag_and_branch_distinguish_hash_and_install_tag() {
+        let default_branch = PkgId::new("git+github.com/mozilla/quux");
+        let v1 = PkgId::new("git+github.com/mozilla/quux?tag=v1.0");
+        let v2 = PkgId::new("git+github.com/mozilla/quux?tag=v2.0");
+        let dev = PkgId::new("git+github.com/mozilla/quux?branch=dev");
+
+        assert!(v1.hash() != v2.hash());
+        assert!(v1.to_str() != v2.to_str());
+        assert!(v1.install_tag() != v2.install_tag());
+        assert!(v1.hash() != default_branch.hash());
+        assert!(dev.hash() != default_branch.hash());
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_git_reference_query_rejected_on_plain_path() {
+        PkgId::new("github.com/mozilla/quux?branch=dev");
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_git_reference_query_rejected_on_registry() {
+        // Only git ids may carry a `?branch=`/`?tag=`/`?rev=` query; a
+        // `?query` on any other source kind (including `registry+`) is
+        // rejected rather than being glued onto the path/short_name.
+        PkgId::new("registry+github.com/mozilla/quux?token=abc123");
+    }
+
+    #[test]
+    fn test_hash_delimiter_runs_before_git_query_split() {
+        // Interaction with the pre-existing `#version` delimiter: without
+        // this ordering, the '?' split runs first and swallows the
+        // trailing '#version', leaving both the branch and the version
+        // wrong.
+        let id = PkgId::new("git+github.com/mozilla/quux?branch=stable#0.9");
+        assert_eq!(id.short_name, ~"quux");
+        assert_eq!(id.version.to_str(), ~"0.9");
+    }
+}
+
+#[cfg(test)]
+mod test_chunk0_3 {
+    use super::PkgId;
+
+    #[test]
+    fn test_no_scheme_by_default() {
+        let id = PkgId::new("github.com/mozilla/quux");
+        assert!(!id.is_system());
+        assert!(!id.to_str().starts_with("sys:"));
+    }
+
+    #[test]
+    fn test_sys_scheme() {
+        let id = PkgId::new("sys:github.com/mozilla/quux");
+        assert!(id.is_system());
+        assert!(id.to_str().starts_with("sys:"));
+    }
+
+    #[test]
+    fn test_sys_scheme_is_case_insensitive() {
+        let id = PkgId::new("SyS:github.com/mozilla/quux");
+        assert!(id.is_system());
+    }
+}
+
+#[cfg(test)]
+mod test_chunk0_4 {
+    use super::PkgId;
+
+    #[test]
+    fn test_trailing_path_version() {
+        let id = PkgId::new("github.com/mozilla/quux/1.2.3");
+        assert_eq!(id.short_name, ~"quux");
+        assert_eq!(id.version.to_str(), ~"1.2.3");
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_trailing_path_version_delimiter_must_not_be_empty() {
+        PkgId::new("github.com/mozilla/quux/");
+    }
+
+    #[test]
+    fn test_trailing_path_version_runs_after_git_query_split() {
+        // Interaction with chunk0-2's `?branch=`/`?tag=`/`?rev=` query:
+        // without this ordering, "1.2.3?branch=dev" is handed to the
+        // version parser as a single (unparsable) component, silently
+        // dropping the version and leaving short_name taken from "1.2.3"
+        // instead of "quux".
+        let id = PkgId::new("git+github.com/mozilla/quux/1.2.3?branch=dev");
+        assert_eq!(id.short_name, ~"quux");
+        assert_eq!(id.version.to_str(), ~"1.2.3");
+    }
+}
+